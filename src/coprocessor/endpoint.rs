@@ -0,0 +1,245 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use kvproto::coprocessor as coppb;
+use kvproto::errorpb;
+
+use super::module::ModuleChain;
+use super::rate_limiter::{CopRequestRateLimiter, RateLimiterConfig};
+use super::reporter::{NoopReporter, Reporter};
+use super::tracker::Tracker;
+use super::{Error, ReqContext, RequestHandler, Result};
+
+/// The default request max handle duration, in seconds, used when the
+/// caller does not provide an explicit deadline.
+pub const DEFAULT_REQUEST_MAX_HANDLE_SECS: u64 = 60;
+
+/// Builds a [`coppb::Response`] carrying the given error, translating it
+/// into the appropriate protobuf error fields so that the client can
+/// decide how to retry.
+pub fn err_resp(e: Error) -> coppb::Response {
+    let mut resp = coppb::Response::new();
+    match e {
+        Error::Outdated(elapsed, tag) => {
+            let status_kv = format!("request outdated after {:?} (tag: {})", elapsed, tag);
+            resp.set_other_error(status_kv);
+        }
+        Error::Cancelled(tag) => {
+            resp.set_other_error(format!("request cancelled by the client (tag: {})", tag));
+        }
+        Error::Full(allow) => {
+            let mut server_is_busy = errorpb::ServerIsBusy::new();
+            server_is_busy.set_reason(format!(
+                "coprocessor running queue is full (allow={})",
+                allow
+            ));
+            let mut errorpb = errorpb::Error::new();
+            errorpb.set_server_is_busy(server_is_busy);
+            resp.set_region_error(errorpb);
+        }
+        Error::Other(err) => {
+            resp.set_other_error(format!("{}", err));
+        }
+    }
+    resp
+}
+
+/// A request queued on an [`EndPointHost`], carrying everything needed to
+/// build and run a [`RequestHandler`] once a read pool thread picks it up.
+///
+/// A `RequestTask` is the concrete, droppable object tied to one gRPC
+/// call: when the gRPC service layer's future for that call is dropped
+/// (the client-side stream closed or the call was cancelled) before the
+/// task finished, so is this `RequestTask`, and its `Drop` impl marks the
+/// request cancelled so a read-pool thread still polling
+/// `ReqContext::check_if_cancelled` stops promptly instead of scanning
+/// until the deadline.
+pub struct RequestTask {
+    pub req: coppb::Request,
+    pub ctx: ReqContext,
+    finished: bool,
+}
+
+impl RequestTask {
+    pub fn new(req: coppb::Request, ctx: ReqContext) -> Self {
+        Self {
+            req,
+            ctx,
+            finished: false,
+        }
+    }
+}
+
+impl Drop for RequestTask {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.ctx.mark_cancelled();
+        }
+    }
+}
+
+/// Work item dispatched onto the coprocessor read pool.
+pub enum Task {
+    Request(RequestTask),
+}
+
+/// Entry point that receives coprocessor RPCs, admits them, and dispatches
+/// them to a [`RequestHandler`]. See `admit` for what admission checks.
+pub struct Host {
+    rate_limiter: CopRequestRateLimiter,
+    reporter: Box<Reporter>,
+    modules: ModuleChain,
+}
+
+impl Host {
+    pub fn new(rate_limiter_config: RateLimiterConfig) -> Self {
+        Self {
+            rate_limiter: CopRequestRateLimiter::new(rate_limiter_config),
+            reporter: box NoopReporter,
+            modules: ModuleChain::new(),
+        }
+    }
+
+    /// Replaces the default no-op trace reporter, e.g. with a
+    /// `reporter::kafka::KafkaReporter` when the `cop-reporter-kafka`
+    /// feature is enabled and a broker is configured.
+    pub fn set_reporter(&mut self, reporter: Box<Reporter>) {
+        self.reporter = reporter;
+    }
+
+    /// Registers a third-party module at the end of the chain. Modules
+    /// run in registration order for both the pre- and post-handle hooks.
+    pub fn register_module(&mut self, module: Box<super::module::CoprocessorModule>) {
+        self.modules.register(module);
+    }
+
+    /// Admits `ctx` for handling: checks the deadline, whether the client
+    /// has already cancelled the request, and the per-type rate limiter,
+    /// before the module chain or the [`RequestHandler`] is run.
+    fn admit(&self, ctx: &ReqContext) -> Result<()> {
+        ctx.deadline.check_if_exceeded()?;
+        ctx.check_if_cancelled()?;
+        self.rate_limiter.acquire(ctx)?;
+        Ok(())
+    }
+
+    /// Runs the unary path for a queued [`RequestTask`], marking it
+    /// finished on the way out so its `Drop` impl does not mistake normal
+    /// completion for client-side cancellation.
+    pub fn handle_request_task(
+        &self,
+        task: &mut RequestTask,
+        handler: Box<RequestHandler>,
+    ) -> Result<coppb::Response> {
+        let result = self.handle_unary_request(task.ctx.clone(), &task.req, handler);
+        task.finished = true;
+        result
+    }
+
+    pub fn handle_unary_request(
+        &self,
+        mut ctx: ReqContext,
+        req: &coppb::Request,
+        mut handler: Box<RequestHandler>,
+    ) -> Result<coppb::Response> {
+        self.admit(&ctx)?;
+        if let Some(resp) = self.modules.pre_handle(&mut ctx, req) {
+            return Ok(resp);
+        }
+
+        let mut tracker = Tracker::new(&ctx);
+        let result = handler.handle_request();
+        let mut metrics = Default::default();
+        handler.collect_metrics_into(&mut metrics);
+
+        let result = result.map(|mut resp| {
+            self.modules.post_handle(&ctx, &mut resp, &metrics);
+            resp
+        });
+
+        tracker.merge_executor_metrics(&mut metrics);
+        self.reporter.report(tracker.into_trace());
+        result
+    }
+
+    /// Runs the streaming path, invoking the post-handle hook once per
+    /// chunk of `HandlerStreamStepResult` so modules see every partial
+    /// response rather than only the final one.
+    pub fn handle_streaming_request(
+        &self,
+        mut ctx: ReqContext,
+        req: &coppb::Request,
+        mut handler: Box<RequestHandler>,
+    ) -> Result<Vec<coppb::Response>> {
+        self.admit(&ctx)?;
+        if let Some(resp) = self.modules.pre_handle(&mut ctx, req) {
+            return Ok(vec![resp]);
+        }
+
+        let mut tracker = Tracker::new(&ctx);
+        let mut chunks = Vec::new();
+        loop {
+            ctx.check_if_cancelled()?;
+            let (chunk, finished) = handler.handle_streaming_request()?;
+            let mut metrics = Default::default();
+            handler.collect_metrics_into(&mut metrics);
+            if let Some(mut resp) = chunk {
+                self.modules.post_handle(&ctx, &mut resp, &metrics);
+                chunks.push(resp);
+            }
+            tracker.merge_executor_metrics(&mut metrics);
+            if finished {
+                break;
+            }
+        }
+        self.reporter.report(tracker.into_trace());
+        Ok(chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    struct OkHandler;
+
+    impl RequestHandler for OkHandler {
+        fn handle_request(&mut self) -> Result<coppb::Response> {
+            Ok(coppb::Response::new())
+        }
+    }
+
+    #[test]
+    fn test_dropping_an_unfinished_task_marks_it_cancelled() {
+        let task = RequestTask::new(coppb::Request::new(), ReqContext::default_for_test());
+        let handle = task.ctx.cancel_handle();
+        drop(task);
+        assert!(handle.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn test_finished_task_is_not_marked_cancelled_on_drop() {
+        let host = Host::new(RateLimiterConfig::new());
+        let mut task = RequestTask::new(coppb::Request::new(), ReqContext::default_for_test());
+        task.ctx
+            .set_max_handle_duration(Duration::from_secs(DEFAULT_REQUEST_MAX_HANDLE_SECS));
+        let handle = task.ctx.cancel_handle();
+
+        host.handle_request_task(&mut task, box OkHandler).unwrap();
+        drop(task);
+
+        assert!(!handle.load(Ordering::Acquire));
+    }
+}
@@ -0,0 +1,42 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::error;
+use std::result;
+use std::time::Duration;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Outdated(elapsed: Duration, tag: &'static str) {
+            description("request is outdated")
+            display("request outdated after {:?} (tag: {})", elapsed, tag)
+        }
+        Cancelled(tag: &'static str) {
+            description("request is cancelled by the client")
+            display("request cancelled by the client (tag: {})", tag)
+        }
+        Full(allow: usize) {
+            description("running queue is full")
+            display("running queue is full (allow={})", allow)
+        }
+        Other(err: Box<dyn error::Error + Sync + Send>) {
+            from()
+            cause(err.as_ref())
+            description(err.description())
+            display("unknown error {:?}", err)
+        }
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
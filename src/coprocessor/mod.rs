@@ -18,15 +18,24 @@ mod endpoint;
 mod error;
 pub mod local_metrics;
 mod metrics;
+mod module;
+mod rate_limiter;
 mod readpool_context;
+pub mod reporter;
 mod statistics;
 mod tracker;
 mod util;
 
 pub use self::endpoint::err_resp;
 pub use self::error::{Error, Result};
+pub use self::module::{CoprocessorModule, ModuleChain};
+pub use self::rate_limiter::{GcraConfig, RateLimiterConfig};
 pub use self::readpool_context::Context as ReadPoolContext;
+pub use self::reporter::Reporter;
+pub use self::tracker::{RequestTrace, Tracker};
 
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use kvproto::{coprocessor as coppb, kvrpcpb};
@@ -41,6 +50,11 @@ const SINGLE_GROUP: &[u8] = b"SingleGroup";
 
 type HandlerStreamStepResult = Result<(Option<coppb::Response>, bool)>;
 
+/// A single coprocessor request being executed. Implementations that loop
+/// over ranges or chunks (DAG executors, analyze, checksum) should poll
+/// `ReqContext::check_if_cancelled` alongside their existing
+/// `Deadline::check_if_exceeded` calls and bail out early with
+/// `Error::Cancelled` once the client has dropped the stream.
 trait RequestHandler: Send {
     fn handle_request(&mut self) -> Result<coppb::Response> {
         panic!("unary request is not supported for this handler");
@@ -100,7 +114,7 @@ impl Deadline {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ReqContext {
     /// The tag of the request
     pub tag: &'static str,
@@ -125,6 +139,10 @@ pub struct ReqContext {
 
     /// The transaction start_ts of the request
     pub txn_start_ts: Option<u64>,
+
+    /// Set when the client has aborted the underlying gRPC stream, so that
+    /// the handler can stop scanning instead of running until the deadline.
+    cancelled: Arc<AtomicBool>,
 }
 
 impl ReqContext {
@@ -146,6 +164,7 @@ impl ReqContext {
             txn_start_ts,
             first_range: ranges.first().cloned(),
             ranges_len: ranges.len(),
+            cancelled: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -154,6 +173,36 @@ impl ReqContext {
         self.deadline.reset(request_max_handle_duration)
     }
 
+    /// Returns a handle that the gRPC layer can use to mark this request as
+    /// cancelled once the client-side stream is dropped or closed.
+    pub fn cancel_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.cancelled)
+    }
+
+    /// Marks this request as cancelled. Called directly by whoever detects
+    /// the client-side stream going away (see `RequestTask`'s `Drop` impl
+    /// for the case where the queued task itself is discarded), or via a
+    /// cloned `cancel_handle()` from a callback that does not otherwise
+    /// have access to the `ReqContext`.
+    pub fn mark_cancelled(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    /// Whether the client has already aborted this request.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Returns `Error::Cancelled` if the client has aborted this request.
+    /// Handlers should call this alongside `deadline.check_if_exceeded()` at
+    /// their existing yield points (e.g. between scanned rows/chunks).
+    pub fn check_if_cancelled(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled(self.tag));
+        }
+        Ok(())
+    }
+
     #[cfg(test)]
     pub fn default_for_test() -> Self {
         Self::new("test", kvrpcpb::Context::new(), &[], None, None, None)
@@ -164,3 +213,36 @@ pub use self::dag::{ScanOn, Scanner};
 pub use self::endpoint::{
     Host as EndPointHost, RequestTask, Task as EndPointTask, DEFAULT_REQUEST_MAX_HANDLE_SECS,
 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_not_cancelled_by_default() {
+        let ctx = ReqContext::default_for_test();
+        assert!(!ctx.is_cancelled());
+        assert!(ctx.check_if_cancelled().is_ok());
+    }
+
+    #[test]
+    fn test_mark_cancelled_is_observed_via_check() {
+        let ctx = ReqContext::default_for_test();
+        ctx.mark_cancelled();
+        assert!(ctx.is_cancelled());
+        match ctx.check_if_cancelled() {
+            Err(Error::Cancelled(_)) => {}
+            other => panic!("expected Error::Cancelled, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cancel_handle_is_shared_with_the_context() {
+        let ctx = ReqContext::default_for_test();
+        let handle = ctx.cancel_handle();
+        // Simulates the gRPC layer flipping the flag once the client-side
+        // stream closes, without holding on to the `ReqContext` itself.
+        handle.store(true, Ordering::Release);
+        assert!(ctx.is_cancelled());
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A pre/post-handle module chain around [`super::RequestHandler`], so
+//! operators and third parties can inspect or rewrite coprocessor traffic
+//! (audit logging, per-tenant quotas, blocking a `REQ_TYPE_*`) without
+//! forking the crate.
+
+use kvproto::coprocessor as coppb;
+
+use super::dag::executor::ExecutorMetrics;
+use super::ReqContext;
+
+/// A single link in the [`ModuleChain`]. Both hooks default to doing
+/// nothing, so a module only needs to implement the one it cares about.
+pub trait CoprocessorModule: Send + Sync {
+    /// Called before a request is dispatched to a `RequestHandler`.
+    /// Returning `Some(response)` short-circuits the chain and the
+    /// handler is never invoked; the response is sent to the client as-is.
+    fn pre_handle(&self, _ctx: &mut ReqContext, _req: &coppb::Request) -> Option<coppb::Response> {
+        None
+    }
+
+    /// Called after a `RequestHandler` produces a response (unary path),
+    /// or once per chunk (streaming path), with the metrics the handler
+    /// reported for that response (on the streaming path, only those of
+    /// the current step). May mutate `resp` in place, e.g. to redact fields.
+    fn post_handle(
+        &self,
+        _ctx: &ReqContext,
+        _resp: &mut coppb::Response,
+        _metrics: &ExecutorMetrics,
+    ) {
+    }
+}
+
+/// An ordered chain of [`CoprocessorModule`]s, invoked around every
+/// `RequestHandler` in registration order for both the pre- and
+/// post-handle hooks.
+#[derive(Default)]
+pub struct ModuleChain {
+    modules: Vec<Box<CoprocessorModule>>,
+}
+
+impl ModuleChain {
+    pub fn new() -> Self {
+        Self {
+            modules: Vec::new(),
+        }
+    }
+
+    /// Appends a module to the end of the chain.
+    pub fn register(&mut self, module: Box<CoprocessorModule>) -> &mut Self {
+        self.modules.push(module);
+        self
+    }
+
+    /// Runs the pre-handle hook of every module in order, stopping and
+    /// returning the first `Some(response)` a module produces.
+    pub fn pre_handle(
+        &self,
+        ctx: &mut ReqContext,
+        req: &coppb::Request,
+    ) -> Option<coppb::Response> {
+        for module in &self.modules {
+            if let Some(resp) = module.pre_handle(ctx, req) {
+                return Some(resp);
+            }
+        }
+        None
+    }
+
+    /// Runs the post-handle hook of every module in order. For the
+    /// streaming path this is invoked once per chunk of
+    /// `HandlerStreamStepResult`, so modules see every partial response.
+    pub fn post_handle(
+        &self,
+        ctx: &ReqContext,
+        resp: &mut coppb::Response,
+        metrics: &ExecutorMetrics,
+    ) {
+        for module in &self.modules {
+            module.post_handle(ctx, resp, metrics);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Records, in a shared log, the order its hooks ran in; optionally
+    /// short-circuits `pre_handle` and tags `post_handle`'s response so
+    /// tests can observe both without a real `RequestHandler`.
+    struct RecordingModule {
+        name: &'static str,
+        log: Arc<Mutex<Vec<&'static str>>>,
+        short_circuit: bool,
+    }
+
+    impl CoprocessorModule for RecordingModule {
+        fn pre_handle(
+            &self,
+            _ctx: &mut ReqContext,
+            _req: &coppb::Request,
+        ) -> Option<coppb::Response> {
+            self.log.lock().unwrap().push(self.name);
+            if self.short_circuit {
+                Some(coppb::Response::new())
+            } else {
+                None
+            }
+        }
+
+        fn post_handle(
+            &self,
+            _ctx: &ReqContext,
+            resp: &mut coppb::Response,
+            _metrics: &ExecutorMetrics,
+        ) {
+            self.log.lock().unwrap().push(self.name);
+            let tagged = format!("{}{}", resp.get_other_error(), self.name);
+            resp.set_other_error(tagged);
+        }
+    }
+
+    fn recording_module(
+        name: &'static str,
+        log: &Arc<Mutex<Vec<&'static str>>>,
+        short_circuit: bool,
+    ) -> Box<CoprocessorModule> {
+        box RecordingModule {
+            name,
+            log: Arc::clone(log),
+            short_circuit,
+        }
+    }
+
+    #[test]
+    fn test_pre_and_post_handle_run_in_registration_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = ModuleChain::new();
+        chain.register(recording_module("a", &log, false));
+        chain.register(recording_module("b", &log, false));
+
+        let mut ctx = ReqContext::default_for_test();
+        assert!(chain.pre_handle(&mut ctx, &coppb::Request::new()).is_none());
+        assert_eq!(*log.lock().unwrap(), vec!["a", "b"]);
+
+        log.lock().unwrap().clear();
+        let mut resp = coppb::Response::new();
+        chain.post_handle(&ctx, &mut resp, &ExecutorMetrics::default());
+        assert_eq!(*log.lock().unwrap(), vec!["a", "b"]);
+        assert_eq!(resp.get_other_error(), "ab");
+    }
+
+    #[test]
+    fn test_pre_handle_short_circuits_and_skips_later_modules() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = ModuleChain::new();
+        chain.register(recording_module("first", &log, true));
+        chain.register(recording_module("second", &log, false));
+
+        let mut ctx = ReqContext::default_for_test();
+        let resp = chain.pre_handle(&mut ctx, &coppb::Request::new());
+
+        assert!(resp.is_some());
+        assert_eq!(*log.lock().unwrap(), vec!["first"]);
+    }
+}
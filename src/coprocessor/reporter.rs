@@ -0,0 +1,117 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pluggable export of finished coprocessor request traces
+//! ([`super::tracker::RequestTrace`]), decoupled from the Prometheus
+//! `metrics`/`local_metrics` path, for offline slow-query and workload
+//! analysis. Backends are expected to own a background worker and a
+//! bounded queue: reporting must never block request handling.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+
+use super::tracker::RequestTrace;
+
+#[cfg(feature = "cop-reporter-kafka")]
+pub mod kafka;
+
+/// Implemented by anything that wants a copy of every finished
+/// coprocessor request trace.
+pub trait Reporter: Send + Sync {
+    fn report(&self, trace: RequestTrace);
+}
+
+/// A `Reporter` that discards every trace. Used when no backend is
+/// configured so callers don't need an `Option<Box<dyn Reporter>>`.
+pub struct NoopReporter;
+
+impl Reporter for NoopReporter {
+    fn report(&self, _trace: RequestTrace) {}
+}
+
+/// Bounded, non-blocking handoff shared by every `Reporter` backend:
+/// traces submitted while the queue is full are dropped (and counted)
+/// rather than stalling the coprocessor thread that produced them.
+pub struct BoundedQueue {
+    sender: SyncSender<RequestTrace>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl BoundedQueue {
+    pub fn new(capacity: usize) -> (Self, Receiver<RequestTrace>) {
+        let (sender, receiver) = sync_channel(capacity);
+        let queue = Self {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        (queue, receiver)
+    }
+
+    /// Enqueues `trace` for the background worker, dropping it (and
+    /// bumping `dropped_count`) instead of blocking when the queue is full.
+    pub fn push(&self, trace: RequestTrace) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(trace) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn dropped_count(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dummy_trace() -> RequestTrace {
+        RequestTrace {
+            tag: "test",
+            txn_start_ts: None,
+            peer: None,
+            ranges_len: 0,
+            scanned_keys: 0,
+            elapsed: Default::default(),
+            executor_metrics: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_push_within_capacity_is_not_dropped() {
+        let (queue, receiver) = BoundedQueue::new(2);
+        queue.push(dummy_trace());
+        queue.push(dummy_trace());
+
+        assert_eq!(queue.dropped_count(), 0);
+        assert_eq!(receiver.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn test_push_past_capacity_is_dropped_and_counted() {
+        // Capacity 1 and nobody draining the receiver: the first push
+        // fills the queue, every subsequent push must be dropped rather
+        // than block the caller.
+        let (queue, _receiver) = BoundedQueue::new(1);
+        queue.push(dummy_trace());
+        queue.push(dummy_trace());
+        queue.push(dummy_trace());
+
+        assert_eq!(queue.dropped_count(), 2);
+    }
+
+    #[test]
+    fn test_noop_reporter_discards_without_panicking() {
+        NoopReporter.report(dummy_trace());
+    }
+}
@@ -0,0 +1,98 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Kafka-backed [`Reporter`] implementation, built only when the
+//! `cop-reporter-kafka` feature is enabled so deployments without a Kafka
+//! broker pay no cost for it.
+
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{BaseProducer, BaseRecord, Producer};
+
+use super::super::tracker::RequestTrace;
+use super::{BoundedQueue, Reporter};
+
+/// A trimmed, JSON-serializable view of a [`RequestTrace`]; kept separate
+/// from the tracker's own type so the hot collection path never has to
+/// derive `Serialize` for executor internals it doesn't report.
+#[derive(serde::Serialize)]
+struct TraceRecord {
+    tag: &'static str,
+    txn_start_ts: Option<u64>,
+    peer: Option<String>,
+    ranges_len: usize,
+    scanned_keys: usize,
+    elapsed_ms: u64,
+}
+
+impl<'a> From<&'a RequestTrace> for TraceRecord {
+    fn from(trace: &'a RequestTrace) -> Self {
+        Self {
+            tag: trace.tag,
+            txn_start_ts: trace.txn_start_ts,
+            peer: trace.peer.clone(),
+            ranges_len: trace.ranges_len,
+            scanned_keys: trace.scanned_keys,
+            elapsed_ms: trace.elapsed.as_millis() as u64,
+        }
+    }
+}
+
+pub struct KafkaReporterConfig {
+    pub brokers: String,
+    pub topic: String,
+    pub queue_capacity: usize,
+}
+
+/// Serializes finished request traces and publishes them to `topic` on a
+/// dedicated background thread; the coprocessor threads only ever touch
+/// the bounded in-memory queue.
+pub struct KafkaReporter {
+    queue: BoundedQueue,
+}
+
+impl KafkaReporter {
+    pub fn new(config: KafkaReporterConfig) -> Self {
+        let (queue, receiver) = BoundedQueue::new(config.queue_capacity);
+        thread::Builder::new()
+            .name("cop-kafka-reporter".to_owned())
+            .spawn(move || Self::run(config.brokers, config.topic, receiver))
+            .expect("failed to spawn cop-kafka-reporter thread");
+        Self { queue }
+    }
+
+    fn run(brokers: String, topic: String, receiver: Receiver<RequestTrace>) {
+        let producer: BaseProducer = ClientConfig::new()
+            .set("bootstrap.servers", &brokers)
+            .create()
+            .expect("failed to create kafka producer for coprocessor reporter");
+
+        for trace in receiver {
+            let record = TraceRecord::from(&trace);
+            let payload = match serde_json::to_vec(&record) {
+                Ok(payload) => payload,
+                Err(_) => continue,
+            };
+            let _ = producer.send(BaseRecord::<(), _>::to(&topic).payload(&payload));
+            producer.poll(std::time::Duration::from_secs(0));
+        }
+    }
+}
+
+impl Reporter for KafkaReporter {
+    fn report(&self, trace: RequestTrace) {
+        self.queue.push(trace);
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright 2016 PingCAP, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Accumulates per-request execution details so they can be handed to an
+//! out-of-band [`super::reporter`] backend for offline slow-query and
+//! workload analysis.
+
+use std::time::Duration;
+
+use util::time::Instant;
+
+use super::dag::executor::ExecutorMetrics;
+use super::ReqContext;
+
+/// A finished request's trace: everything a reporter needs to decide
+/// whether a request was slow and why, without re-deriving it from the
+/// original protobuf request.
+#[derive(Debug, Clone)]
+pub struct RequestTrace {
+    pub tag: &'static str,
+    pub txn_start_ts: Option<u64>,
+    pub peer: Option<String>,
+    pub ranges_len: usize,
+    pub scanned_keys: usize,
+    pub elapsed: Duration,
+    pub executor_metrics: ExecutorMetrics,
+}
+
+/// Tracks one request from admission to completion, then hands off a
+/// [`RequestTrace`] snapshot for reporting.
+pub struct Tracker {
+    start_time: Instant,
+    tag: &'static str,
+    txn_start_ts: Option<u64>,
+    peer: Option<String>,
+    ranges_len: usize,
+    scanned_keys: usize,
+    executor_metrics: ExecutorMetrics,
+}
+
+impl Tracker {
+    pub fn new(ctx: &ReqContext) -> Self {
+        Self {
+            start_time: Instant::now_coarse(),
+            tag: ctx.tag,
+            txn_start_ts: ctx.txn_start_ts,
+            peer: ctx.peer.clone(),
+            ranges_len: ctx.ranges_len,
+            scanned_keys: 0,
+            executor_metrics: ExecutorMetrics::default(),
+        }
+    }
+
+    pub fn on_scanned_keys(&mut self, count: usize) {
+        self.scanned_keys += count;
+    }
+
+    pub fn merge_executor_metrics(&mut self, metrics: &mut ExecutorMetrics) {
+        self.executor_metrics.merge(metrics);
+    }
+
+    /// Finalizes the tracker, returning the trace to be passed to a
+    /// [`super::reporter::Reporter`].
+    pub fn into_trace(self) -> RequestTrace {
+        RequestTrace {
+            tag: self.tag,
+            txn_start_ts: self.txn_start_ts,
+            peer: self.peer,
+            ranges_len: self.ranges_len,
+            scanned_keys: self.scanned_keys,
+            elapsed: self.start_time.elapsed(),
+            executor_metrics: self.executor_metrics,
+        }
+    }
+}